@@ -0,0 +1,291 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Attestation plumbing for the Keep.
+//!
+//! This is deliberately thin: it asks the platform (SEV or SGX, if either is
+//! present) for evidence over some caller-supplied `report_data`, and hands
+//! back whatever bytes it got. Today it's used for three rather different
+//! things - retrieving a previously-sealed key (`retrieve_existing_key` in
+//! `main.rs`), producing a quote/report to embed in the RA-TLS certificate,
+//! and attesting over a batch's Merkle root (`sign_root`). All go through
+//! the same `attest()` entry point.
+//!
+//! TODO - this is all still stubbed out pending real SGX/SEV ioctl wiring.
+
+use std::error::Error;
+use std::fmt;
+
+/// Result of an attestation call.
+///
+/// The `usize` in `Sev`/`Sgx` is overloaded, same as the SEV key-retrieval
+/// path already relies on: if the caller's `output` buffer was too small to
+/// hold the evidence, it's the number of bytes the caller needs to retry
+/// with; if the buffer was big enough, it's the number of bytes actually
+/// written into it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Attestation {
+    Sev(usize),
+    Sgx(usize),
+    None,
+}
+
+#[derive(Debug)]
+pub enum AttestationError {
+    Unsupported,
+    Platform(String),
+}
+
+impl fmt::Display for AttestationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AttestationError::Unsupported => write!(f, "no attestation platform available"),
+            AttestationError::Platform(msg) => write!(f, "attestation platform error: {}", msg),
+        }
+    }
+}
+
+impl Error for AttestationError {}
+
+enum Platform {
+    Sev,
+    Sgx,
+    None,
+}
+
+/// Figure out which (if any) attestation platform we're running under.
+///
+/// FIXME - this should probe `/dev/sev` / the SGX driver rather than an env
+/// var, but that needs root and a real enclave to test against.
+fn detect_platform() -> Platform {
+    match std::env::var("ENARX_ATTESTATION_PLATFORM").as_deref() {
+        Ok("sev") => Platform::Sev,
+        Ok("sgx") => Platform::Sgx,
+        _ => Platform::None,
+    }
+}
+
+/// Ask the platform for attestation evidence binding `report_data`.
+///
+/// `output` is both the offered buffer size and, on success with a big
+/// enough buffer, where the evidence is written. See `Attestation` above for
+/// what the returned length means in each case.
+pub fn attest(report_data: &[u8], output: &mut Vec<u8>) -> Result<Attestation, AttestationError> {
+    match detect_platform() {
+        Platform::Sev => attest_sev(report_data, output),
+        Platform::Sgx => attest_sgx(report_data, output),
+        Platform::None => Ok(Attestation::None),
+    }
+}
+
+fn attest_sev(report_data: &[u8], output: &mut Vec<u8>) -> Result<Attestation, AttestationError> {
+    //TODO - talk to /dev/sev for a real certificate chain + sealed key.
+    const SEALED_KEY_LEN: usize = 2048 / 8;
+    if output.len() < SEALED_KEY_LEN {
+        return Ok(Attestation::Sev(SEALED_KEY_LEN));
+    }
+    //No real backend: zero the buffer and embed report_data at the front,
+    //same as attest_sgx.
+    for byte in output.iter_mut() {
+        *byte = 0;
+    }
+    let copy_len = report_data.len().min(output.len());
+    output[..copy_len].copy_from_slice(&report_data[..copy_len]);
+    Ok(Attestation::Sev(SEALED_KEY_LEN))
+}
+
+fn attest_sgx(report_data: &[u8], output: &mut Vec<u8>) -> Result<Attestation, AttestationError> {
+    //TODO - issue the real EREPORT/quote-generation ioctls. For now we stand
+    // in a "quote" up so that the RA-TLS plumbing above us has something
+    // structurally shaped like a quote (report_data embedded, rest zeroed)
+    // to exercise the extension-embedding and verification code paths.
+    const QUOTE_LEN: usize = 4096;
+    if output.len() < QUOTE_LEN {
+        return Ok(Attestation::Sgx(QUOTE_LEN));
+    }
+    for byte in output.iter_mut() {
+        *byte = 0;
+    }
+    let copy_len = report_data.len().min(output.len());
+    output[..copy_len].copy_from_slice(&report_data[..copy_len]);
+    Ok(Attestation::Sgx(QUOTE_LEN))
+}
+
+/// Private enterprise OID arc used for our custom X.509 v3 extensions.
+///
+/// `.1` carries an SGX quote, `.2` an SEV report. Not IANA-registered -
+/// these only need to be stable within a Keep/peer pair.
+pub const OID_SGX_QUOTE: &str = "1.3.6.1.4.1.99999.1";
+pub const OID_SEV_REPORT: &str = "1.3.6.1.4.1.99999.2";
+
+/// Request a quote/report over `report_data` and return the OID it should be
+/// embedded under alongside the raw evidence bytes, or `None` if this Keep
+/// isn't running under an attestable platform.
+pub fn attest_for_report_data(
+    report_data: &[u8; 32],
+) -> Result<Option<(&'static str, Vec<u8>)>, AttestationError> {
+    let mut buf = vec![0u8; 4096];
+    let result = attest(report_data, &mut buf)?;
+    match result {
+        Attestation::Sgx(len) => {
+            if buf.len() < len {
+                buf.resize(len, 0);
+                attest(report_data, &mut buf)?;
+            }
+            buf.truncate(len);
+            Ok(Some((OID_SGX_QUOTE, buf)))
+        }
+        Attestation::Sev(len) => {
+            if buf.len() < len {
+                buf.resize(len, 0);
+                attest(report_data, &mut buf)?;
+            }
+            buf.truncate(len);
+            Ok(Some((OID_SEV_REPORT, buf)))
+        }
+        Attestation::None => Ok(None),
+    }
+}
+
+/// Have the enclave attest over `root` (e.g. a batch's Merkle root - see
+/// `batch.rs`), standing in for a dedicated signing key: the returned quote
+/// embeds `root` as its `report_data`, so verifying the quote is equivalent
+/// to verifying a signature over it.
+///
+/// Returns an empty Vec (rather than erroring) when this Keep isn't running
+/// under an attestable platform, so batching still works - unsigned - in
+/// dev/test environments.
+pub fn sign_root(root: &[u8; 32]) -> Result<Vec<u8>, AttestationError> {
+    match attest_for_report_data(root)? {
+        Some((_, quote)) => Ok(quote),
+        None => Ok(Vec::new()),
+    }
+}
+
+/// Evidence pulled back out of a peer's RA-TLS certificate.
+#[derive(Debug)]
+pub struct EmbeddedEvidence {
+    pub oid: String,
+    pub quote: Vec<u8>,
+}
+
+#[derive(Debug)]
+pub enum VerifyError {
+    NoAttestationExtension,
+    UnknownOid(String),
+    ReportDataMismatch,
+    VendorRootUntrusted,
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VerifyError::NoAttestationExtension => {
+                write!(f, "peer certificate carries no attestation extension")
+            }
+            VerifyError::UnknownOid(oid) => write!(f, "unrecognised attestation OID {}", oid),
+            VerifyError::ReportDataMismatch => write!(
+                f,
+                "quote report_data does not match the certificate's public key"
+            ),
+            VerifyError::VendorRootUntrusted => {
+                write!(f, "quote did not chain to a trusted vendor root")
+            }
+        }
+    }
+}
+
+impl Error for VerifyError {}
+
+/// Outcome of checking embedded evidence's `report_data` binding.
+///
+/// Deliberately not a plain `bool`/`()` - see `verify_embedded_attestation`.
+/// `ReportDataBoundOnly` is the honest result for a stubbed-out
+/// `attest_sgx`/`attest_sev` backend today: it means the 32 bytes check out,
+/// but nothing has confirmed the quote/report was actually produced by a
+/// genuine Intel/AMD vendor-signed backend. Callers doing real RA-TLS
+/// channel binding MUST treat that the same as "not verified" and must not
+/// collapse it into a plain success.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VerifyOutcome {
+    /// `report_data` matched *and* the quote/report chained to a trusted
+    /// vendor root. Not reachable yet - see the `TODO` below.
+    Verified,
+    /// `report_data` matched, but the vendor-root chain was never checked
+    /// (no such infrastructure exists yet). This is NOT proof the peer is
+    /// really attested - only that the evidence handed to us is shaped
+    /// correctly and bound to this certificate's public key.
+    ReportDataBoundOnly,
+}
+
+/// Check `evidence`'s `report_data` against the expected binding (the
+/// SHA-256 hash of `cert_pubkey_der`).
+///
+/// Callers get `evidence` by pulling the `OID_SGX_QUOTE` / `OID_SEV_REPORT`
+/// extension out of the peer's leaf certificate (see
+/// `main.rs::extract_attestation_extension`).
+///
+/// TODO - this does not verify the quote/report signature chains up to the
+/// Intel/AMD vendor root; that infrastructure isn't wired up yet. Until it
+/// is, this can only ever return `Ok(VerifyOutcome::ReportDataBoundOnly)`,
+/// never `Ok(VerifyOutcome::Verified)` - callers must not treat the former
+/// as a substitute for real RA-TLS channel binding.
+pub fn verify_embedded_attestation(
+    evidence: &EmbeddedEvidence,
+    cert_pubkey_der: &[u8],
+) -> Result<VerifyOutcome, VerifyError> {
+    let expected_report_data = openssl::sha::sha256(cert_pubkey_der);
+
+    match evidence.oid.as_str() {
+        OID_SGX_QUOTE | OID_SEV_REPORT => {
+            if evidence.quote.len() < expected_report_data.len()
+                || &evidence.quote[..expected_report_data.len()] != expected_report_data.as_ref()
+            {
+                return Err(VerifyError::ReportDataMismatch);
+            }
+            Ok(VerifyOutcome::ReportDataBoundOnly)
+        }
+        other => Err(VerifyError::UnknownOid(other.to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accepts_a_quote_bound_to_the_expected_report_data() {
+        let cert_pubkey_der = b"pretend this is a DER-encoded public key";
+        let report_data = openssl::sha::sha256(cert_pubkey_der);
+        let mut quote = report_data.to_vec();
+        quote.extend_from_slice(&[0u8; 32]); //rest of the (stubbed) quote
+
+        let evidence = EmbeddedEvidence {
+            oid: OID_SGX_QUOTE.to_string(),
+            quote,
+        };
+        assert_eq!(
+            verify_embedded_attestation(&evidence, cert_pubkey_der).unwrap(),
+            VerifyOutcome::ReportDataBoundOnly
+        );
+    }
+
+    #[test]
+    fn rejects_a_quote_bound_to_the_wrong_key() {
+        let evidence = EmbeddedEvidence {
+            oid: OID_SEV_REPORT.to_string(),
+            quote: vec![0u8; 64],
+        };
+        let result = verify_embedded_attestation(&evidence, b"some other public key");
+        assert!(matches!(result, Err(VerifyError::ReportDataMismatch)));
+    }
+
+    #[test]
+    fn rejects_an_unrecognised_oid() {
+        let evidence = EmbeddedEvidence {
+            oid: "1.2.3.4".to_string(),
+            quote: vec![0u8; 64],
+        };
+        let result = verify_embedded_attestation(&evidence, b"whatever");
+        assert!(matches!(result, Err(VerifyError::UnknownOid(_))));
+    }
+}
@@ -0,0 +1,259 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Batched attestation over concurrently-submitted workloads.
+//!
+//! Rather than attest each workload launch in isolation, `BatchAttestor`
+//! collects the request hashes of everything submitted in a short window,
+//! builds a Merkle tree over them, and has the enclave sign just the tree
+//! root (see `attestation::sign_root`). Each submitter gets back its own
+//! leaf hash, the inclusion path up to the root, and the one signature -
+//! letting a client confirm its workload was admitted with one signature
+//! check plus O(log n) hashing, instead of needing a signature per
+//! request.
+
+use openssl::hash::{hash, MessageDigest};
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::sync::oneshot;
+
+const LEAF_LEN: usize = 32;
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub enum PathStep {
+    //Sibling hash, and which side it sits on relative to the node we're
+    //climbing from.
+    Left([u8; LEAF_LEN]),
+    Right([u8; LEAF_LEN]),
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct AttestationResponse {
+    pub leaf_hash: [u8; LEAF_LEN],
+    pub inclusion_path: Vec<PathStep>,
+    pub root: [u8; LEAF_LEN],
+    //Quote/report binding the root, from `attestation::sign_root`.
+    pub signature: Vec<u8>,
+}
+
+fn hash_leaf(request_hash: &[u8; LEAF_LEN]) -> [u8; LEAF_LEN] {
+    //Domain-separate leaves from internal nodes so a leaf can't be
+    //replayed as an internal node (the classic second-preimage trick
+    //against naive Merkle trees).
+    let mut input = Vec::with_capacity(1 + LEAF_LEN);
+    input.push(0u8);
+    input.extend_from_slice(request_hash);
+    digest(&input)
+}
+
+fn hash_pair(left: &[u8; LEAF_LEN], right: &[u8; LEAF_LEN]) -> [u8; LEAF_LEN] {
+    let mut input = Vec::with_capacity(1 + 2 * LEAF_LEN);
+    input.push(1u8);
+    input.extend_from_slice(left);
+    input.extend_from_slice(right);
+    digest(&input)
+}
+
+fn digest(input: &[u8]) -> [u8; LEAF_LEN] {
+    let digest = hash(MessageDigest::sha256(), input).expect("sha256 cannot fail");
+    let mut out = [0u8; LEAF_LEN];
+    out.copy_from_slice(&digest);
+    out
+}
+
+/// Build every level of the tree, bottom (leaves) to top (root), pairwise
+/// hashing sorted leaves and promoting the odd one out at each level
+/// unchanged.
+fn build_levels(mut leaves: Vec<[u8; LEAF_LEN]>) -> Vec<Vec<[u8; LEAF_LEN]>> {
+    leaves.sort_unstable();
+    let mut levels = vec![leaves];
+    while levels.last().unwrap().len() > 1 {
+        let current = levels.last().unwrap();
+        let mut next = Vec::with_capacity((current.len() + 1) / 2);
+        let mut iter = current.chunks(2);
+        for pair in &mut iter {
+            if pair.len() == 2 {
+                next.push(hash_pair(&pair[0], &pair[1]));
+            } else {
+                //Odd one out at this level - promote it unchanged.
+                next.push(pair[0]);
+            }
+        }
+        levels.push(next);
+    }
+    levels
+}
+
+/// Inclusion path for `leaf` from `levels` (as built by `build_levels`), or
+/// `None` if it isn't present in the bottom level.
+fn inclusion_path(levels: &[Vec<[u8; LEAF_LEN]>], leaf: &[u8; LEAF_LEN]) -> Option<Vec<PathStep>> {
+    let mut index = levels[0].iter().position(|l| l == leaf)?;
+    let mut path = Vec::new();
+    for level in &levels[..levels.len() - 1] {
+        let sibling_index = if index % 2 == 0 { index + 1 } else { index - 1 };
+        if let Some(sibling) = level.get(sibling_index) {
+            path.push(if index % 2 == 0 {
+                PathStep::Right(*sibling)
+            } else {
+                PathStep::Left(*sibling)
+            });
+        }
+        //If there was no sibling (odd one out), this node was promoted
+        //unchanged, and the next level's index is the same position.
+        index /= 2;
+    }
+    Some(path)
+}
+
+/// Recompute the root from `leaf` and `path`, for a client to compare
+/// against the signed root it was given.
+pub fn verify_path(leaf: [u8; LEAF_LEN], path: &[PathStep], expected_root: [u8; LEAF_LEN]) -> bool {
+    let mut node = leaf;
+    for step in path {
+        node = match step {
+            PathStep::Left(sibling) => hash_pair(sibling, &node),
+            PathStep::Right(sibling) => hash_pair(&node, sibling),
+        };
+    }
+    node == expected_root
+}
+
+struct Pending {
+    leaf_hash: [u8; LEAF_LEN],
+    reply: oneshot::Sender<AttestationResponse>,
+}
+
+/// Collects submitted request hashes and periodically (or once a batch
+/// fills up) builds and signs a Merkle tree over them.
+pub struct BatchAttestor {
+    pending: Mutex<Vec<Pending>>,
+    max_batch: usize,
+}
+
+impl BatchAttestor {
+    pub fn new(max_batch: usize) -> BatchAttestor {
+        BatchAttestor {
+            pending: Mutex::new(Vec::new()),
+            max_batch,
+        }
+    }
+
+    /// Queue `request_hash` for the next batch and return a receiver that
+    /// resolves once this Keep signs a tree root that includes it.
+    pub fn submit(&self, request_hash: [u8; LEAF_LEN]) -> oneshot::Receiver<AttestationResponse> {
+        let (reply, receiver) = oneshot::channel();
+        let leaf_hash = hash_leaf(&request_hash);
+        let mut pending = self.pending.lock().unwrap();
+        pending.push(Pending { leaf_hash, reply });
+        let due = pending.len() >= self.max_batch;
+        drop(pending);
+        if due {
+            self.flush();
+        }
+        receiver
+    }
+
+    /// Build a tree over everything queued right now, sign the root, and
+    /// resolve every submitter's receiver with its inclusion proof. A no-op
+    /// if nothing is pending.
+    pub fn flush(&self) {
+        let batch: Vec<Pending> = std::mem::take(&mut *self.pending.lock().unwrap());
+        if batch.is_empty() {
+            return;
+        }
+
+        let leaves: Vec<[u8; LEAF_LEN]> = batch.iter().map(|p| p.leaf_hash).collect();
+        let levels = build_levels(leaves);
+        let root = levels.last().unwrap()[0];
+        let signature = crate::attestation::sign_root(&root).unwrap_or_default();
+
+        for entry in batch {
+            //Unwrap-free: if nobody's listening any more (e.g. the
+            //connection was dropped), there's nothing to deliver to.
+            let inclusion_path = inclusion_path(&levels, &entry.leaf_hash).unwrap_or_default();
+            let _ = entry.reply.send(AttestationResponse {
+                leaf_hash: entry.leaf_hash,
+                inclusion_path,
+                root,
+                signature: signature.clone(),
+            });
+        }
+    }
+}
+
+/// Spawn the background task that flushes `attestor` every `interval`, so a
+/// batch doesn't wait forever for `max_batch` to fill up under light load.
+pub fn spawn_periodic_flush(attestor: std::sync::Arc<BatchAttestor>, interval: Duration) {
+    tokio::task::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            attestor.flush();
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn leaf(byte: u8) -> [u8; LEAF_LEN] {
+        [byte; LEAF_LEN]
+    }
+
+    #[test]
+    fn inclusion_path_verifies_for_every_leaf_in_an_odd_sized_tree() {
+        let hashed: Vec<_> = (0..5u8).map(|b| hash_leaf(&leaf(b))).collect();
+        let levels = build_levels(hashed.clone());
+        let root = *levels.last().unwrap().first().unwrap();
+
+        for leaf_hash in &hashed {
+            let path = inclusion_path(&levels, leaf_hash).expect("leaf is in the tree");
+            assert!(verify_path(*leaf_hash, &path, root));
+        }
+    }
+
+    #[test]
+    fn verify_path_rejects_a_leaf_that_was_not_admitted() {
+        let hashed: Vec<_> = (0..4u8).map(|b| hash_leaf(&leaf(b))).collect();
+        let levels = build_levels(hashed.clone());
+        let root = *levels.last().unwrap().first().unwrap();
+
+        let path = inclusion_path(&levels, &hashed[0]).unwrap();
+        let forged_leaf = hash_leaf(&leaf(99));
+        assert!(!verify_path(forged_leaf, &path, root));
+    }
+
+    #[test]
+    fn inclusion_path_is_none_for_a_leaf_not_in_the_tree() {
+        let hashed: Vec<_> = (0..3u8).map(|b| hash_leaf(&leaf(b))).collect();
+        let levels = build_levels(hashed);
+        assert!(inclusion_path(&levels, &hash_leaf(&leaf(99))).is_none());
+    }
+
+    #[tokio::test]
+    async fn batch_attestor_resolves_every_submitter_against_the_same_root() {
+        let attestor = BatchAttestor::new(32);
+        let a = attestor.submit([1u8; LEAF_LEN]);
+        let b = attestor.submit([2u8; LEAF_LEN]);
+        attestor.flush();
+
+        let resp_a = a.await.unwrap();
+        let resp_b = b.await.unwrap();
+        assert_eq!(resp_a.root, resp_b.root);
+        assert!(verify_path(
+            resp_a.leaf_hash,
+            &resp_a.inclusion_path,
+            resp_a.root
+        ));
+        assert!(verify_path(
+            resp_b.leaf_hash,
+            &resp_b.inclusion_path,
+            resp_b.root
+        ));
+    }
+
+    #[test]
+    fn flush_with_nothing_pending_is_a_no_op() {
+        BatchAttestor::new(32).flush();
+    }
+}
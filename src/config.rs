@@ -0,0 +1,473 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Keep configuration.
+//!
+//! `load` builds the full `Config` from a YAML file (path given on
+//! the command line or via `ENARX_WASMLDR_CONFIG`), with individual fields
+//! overridable by environment variables and everything else falling back
+//! to the defaults this binary shipped with before this module existed.
+//! The result is validated (bad IPs, empty secrets, etc. are rejected with
+//! a `ConfigError`) before `main()` ever touches a socket.
+//!
+//! The trust side of things - the handshake's two modes - are part of the
+//! same `Config`:
+//!
+//! - `SharedSecret`: both the Keep's static keypair *and* the single peer
+//!   key it trusts are derived deterministically from an operator-supplied
+//!   secret string. Works for the common case of "one tenant, one Keep"
+//!   without needing a PKI - both sides compute the same keypair from the
+//!   same secret, so each side's own public key *is* the key it should see
+//!   the other side present.
+//! - `Explicit`: the Keep generates a random static keypair (which an
+//!   operator then needs to distribute out of band) and trusts whichever
+//!   peer public keys are listed in config.
+
+use openssl::derive::Deriver;
+use openssl::hash::{hash, MessageDigest};
+use openssl::pkey::{Id, PKey, Private};
+use openssl::rand::rand_bytes;
+use std::fmt;
+use std::net::SocketAddr;
+
+#[derive(Debug)]
+pub enum TrustMode {
+    SharedSecret(String),
+    Explicit { peer_public_keys: Vec<Vec<u8>> },
+}
+
+/// Which socket(s) the Keep accepts workload submissions on.
+///
+/// `WebSocket` exists for tenants sitting behind a proxy that only forwards
+/// HTTP(S)/WebSocket traffic and can't reach the raw TLS socket directly -
+/// see `main.rs`'s `serve_websocket`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transport {
+    RawTls,
+    WebSocket,
+    Both,
+}
+
+impl Transport {
+    pub fn wants_raw_tls(self) -> bool {
+        matches!(self, Transport::RawTls | Transport::Both)
+    }
+
+    pub fn wants_websocket(self) -> bool {
+        matches!(self, Transport::WebSocket | Transport::Both)
+    }
+}
+
+/// The X.509 subject fields and validity window `generate_credentials` (in
+/// `main.rs`) stamps into the self-signed server certificate.
+#[derive(Debug, Clone)]
+pub struct CertConfig {
+    pub country: String,
+    pub organization: String,
+    pub validity_days: u32,
+}
+
+impl Default for CertConfig {
+    fn default() -> CertConfig {
+        CertConfig {
+            country: "GB".to_string(),
+            organization: "enarx-test".to_string(),
+            validity_days: 7,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Config {
+    pub trust_mode: TrustMode,
+    //How many messages (resp. how long) a session key generation may be
+    // used for before handshake.rs rolls over to a fresh one.
+    pub rekey_after_messages: u64,
+    pub rekey_after_seconds: u64,
+    pub transport: Transport,
+    //Separate from the raw-TLS listen address, since a WebSocket front-end
+    //proxy is typically relaying from a different host/port than the one
+    //clients that can reach the Keep directly would use.
+    pub websocket_listen_addr: SocketAddr,
+    pub listen_addr: SocketAddr,
+    //The only value `get_credentials_bytes` (in `main.rs`) currently
+    //understands is `generate` (self-signed, freshly generated key);
+    //anything else is rejected by `load` below. A `retrieve-existing` mode
+    //(pulling a previously-sealed key via attestation) has been discussed
+    //but isn't wired up as a real option yet.
+    pub key_source: String,
+    pub key_length: u32,
+    pub cert: CertConfig,
+}
+
+impl Config {
+    pub fn shared_secret(secret: impl Into<String>) -> Config {
+        Config {
+            trust_mode: TrustMode::SharedSecret(secret.into()),
+            ..Config::defaults()
+        }
+    }
+
+    pub fn explicit_trust(peer_public_keys: Vec<Vec<u8>>) -> Config {
+        Config {
+            trust_mode: TrustMode::Explicit { peer_public_keys },
+            ..Config::defaults()
+        }
+    }
+
+    pub fn with_transport(mut self, transport: Transport) -> Config {
+        self.transport = transport;
+        self
+    }
+
+    fn defaults() -> Config {
+        Config {
+            trust_mode: TrustMode::SharedSecret("enarx-wasmldr-dev-secret".to_string()),
+            rekey_after_messages: 1000,
+            rekey_after_seconds: 3600,
+            transport: Transport::RawTls,
+            websocket_listen_addr: "0.0.0.0:3041".parse().unwrap(),
+            //This was the previously-hardcoded address of
+            //rome.sev.lab.enarx.dev (2021-01-07) - kept as the fallback so
+            //an operator who doesn't supply a config file yet gets the same
+            //behaviour as before this module existed.
+            listen_addr: "147.75.68.181:3040".parse().unwrap(),
+            key_source: "generate".to_string(),
+            key_length: 2048,
+            cert: CertConfig::default(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Read(String, std::io::Error),
+    Parse(serde_yaml::Error),
+    InvalidListenAddr(String),
+    InvalidWebsocketListenAddr(String),
+    InvalidKeyLength(String),
+    KeyLengthTooSmall(u32),
+    InvalidCertValidityDays(String),
+    UnknownKeySource(String),
+    InvalidCertCountry(String),
+    EmptySharedSecret,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::Read(path, e) => write!(f, "could not read {}: {}", path, e),
+            ConfigError::Parse(e) => write!(f, "could not parse config YAML: {}", e),
+            ConfigError::InvalidListenAddr(s) => write!(f, "invalid listen_addr {:?}", s),
+            ConfigError::InvalidWebsocketListenAddr(s) => {
+                write!(f, "invalid websocket.listen_addr {:?}", s)
+            }
+            ConfigError::InvalidKeyLength(s) => {
+                write!(f, "invalid ENARX_WASMLDR_KEY_LENGTH {:?}: must be a u32", s)
+            }
+            ConfigError::KeyLengthTooSmall(bits) => write!(
+                f,
+                "key_length {} is too small to generate an RSA key from (minimum {})",
+                bits, MIN_RSA_KEY_BITS
+            ),
+            ConfigError::InvalidCertValidityDays(s) => write!(
+                f,
+                "invalid ENARX_WASMLDR_CERT_VALIDITY_DAYS {:?}: must be a u32",
+                s
+            ),
+            ConfigError::UnknownKeySource(s) => write!(
+                f,
+                "unknown key_source {:?}: only \"generate\" is supported",
+                s
+            ),
+            ConfigError::InvalidCertCountry(s) => write!(
+                f,
+                "invalid cert.country {:?}: must be a 2-letter country code",
+                s
+            ),
+            ConfigError::EmptySharedSecret => write!(f, "trust.shared_secret must not be empty"),
+        }
+    }
+}
+
+/// Below this, OpenSSL can't reliably build an RSA key (there isn't enough
+/// room for two distinct primes plus padding) - `Rsa::generate` in
+/// `main.rs::generate_credentials` would otherwise panic on its `.unwrap()`
+/// instead of the server ever binding a socket.
+const MIN_RSA_KEY_BITS: u32 = 512;
+
+impl std::error::Error for ConfigError {}
+
+/// Mirrors `Config`, but every field is optional: `None` means "use the
+/// built-in default, or let an environment variable override it".
+#[derive(Debug, Default, serde::Deserialize)]
+#[serde(default)]
+struct RawConfig {
+    listen_addr: Option<String>,
+    key_source: Option<String>,
+    key_length: Option<u32>,
+    cert: RawCertConfig,
+    trust: RawTrustConfig,
+    websocket: RawWebsocketConfig,
+    rekey_after_messages: Option<u64>,
+    rekey_after_seconds: Option<u64>,
+}
+
+#[derive(Debug, Default, serde::Deserialize)]
+#[serde(default)]
+struct RawCertConfig {
+    country: Option<String>,
+    organization: Option<String>,
+    validity_days: Option<u32>,
+}
+
+#[derive(Debug, Default, serde::Deserialize)]
+#[serde(default)]
+struct RawTrustConfig {
+    //"shared-secret" (default) or "explicit"
+    mode: Option<String>,
+    shared_secret: Option<String>,
+    peer_public_keys: Option<Vec<String>>,
+}
+
+#[derive(Debug, Default, serde::Deserialize)]
+#[serde(default)]
+struct RawWebsocketConfig {
+    //"raw-tls" (default), "websocket" or "both"
+    transport: Option<String>,
+    listen_addr: Option<String>,
+}
+
+/// Build the `Config` the rest of the binary runs with.
+///
+/// Each field takes, in order of precedence, its `ENARX_WASMLDR_*`
+/// environment variable (for fields that have one), then the YAML file,
+/// then the built-in default. The YAML file itself is found at
+/// `config_path_arg`, falling back to `ENARX_WASMLDR_CONFIG` if that's
+/// `None`; if neither is set, `load` just applies env var overrides on top
+/// of the defaults.
+pub fn load(config_path_arg: Option<&str>) -> Result<Config, ConfigError> {
+    let config_path = config_path_arg
+        .map(str::to_string)
+        .or_else(|| std::env::var("ENARX_WASMLDR_CONFIG").ok());
+
+    let raw: RawConfig = match config_path {
+        Some(path) => {
+            let contents = std::fs::read_to_string(&path)
+                .map_err(|e| ConfigError::Read(path.clone(), e))?;
+            serde_yaml::from_str(&contents).map_err(ConfigError::Parse)?
+        }
+        None => RawConfig::default(),
+    };
+
+    let mut config = Config::defaults();
+
+    if let Some(listen_addr) = env_override("ENARX_WASMLDR_LISTEN_ADDR").or(raw.listen_addr) {
+        config.listen_addr = listen_addr
+            .parse()
+            .map_err(|_| ConfigError::InvalidListenAddr(listen_addr))?;
+    }
+
+    if let Some(key_source) = env_override("ENARX_WASMLDR_KEY_SOURCE").or(raw.key_source) {
+        if key_source != "generate" {
+            return Err(ConfigError::UnknownKeySource(key_source));
+        }
+        config.key_source = key_source;
+    }
+
+    if let Some(key_length) = env_override("ENARX_WASMLDR_KEY_LENGTH") {
+        config.key_length = key_length
+            .parse()
+            .map_err(|_| ConfigError::InvalidKeyLength(key_length))?;
+    } else if let Some(key_length) = raw.key_length {
+        config.key_length = key_length;
+    }
+    if config.key_length < MIN_RSA_KEY_BITS {
+        return Err(ConfigError::KeyLengthTooSmall(config.key_length));
+    }
+
+    if let Some(country) = env_override("ENARX_WASMLDR_CERT_COUNTRY").or(raw.cert.country) {
+        if country.len() != 2 || !country.chars().all(|c| c.is_ascii_alphabetic()) {
+            return Err(ConfigError::InvalidCertCountry(country));
+        }
+        config.cert.country = country;
+    }
+    if let Some(organization) =
+        env_override("ENARX_WASMLDR_CERT_ORGANIZATION").or(raw.cert.organization)
+    {
+        config.cert.organization = organization;
+    }
+    if let Some(validity_days) = env_override("ENARX_WASMLDR_CERT_VALIDITY_DAYS") {
+        config.cert.validity_days = validity_days
+            .parse()
+            .map_err(|_| ConfigError::InvalidCertValidityDays(validity_days))?;
+    } else if let Some(validity_days) = raw.cert.validity_days {
+        config.cert.validity_days = validity_days;
+    }
+
+    if let Some(rekey_after_messages) = raw.rekey_after_messages {
+        config.rekey_after_messages = rekey_after_messages;
+    }
+    if let Some(rekey_after_seconds) = raw.rekey_after_seconds {
+        config.rekey_after_seconds = rekey_after_seconds;
+    }
+
+    if let Some(transport) = env_override("ENARX_WASMLDR_TRANSPORT").or(raw.websocket.transport) {
+        config.transport = match transport.as_str() {
+            "websocket" => Transport::WebSocket,
+            "both" => Transport::Both,
+            _ => Transport::RawTls,
+        };
+    }
+    if let Some(ws_listen_addr) =
+        env_override("ENARX_WASMLDR_WEBSOCKET_LISTEN_ADDR").or(raw.websocket.listen_addr)
+    {
+        config.websocket_listen_addr = ws_listen_addr
+            .parse()
+            .map_err(|_| ConfigError::InvalidWebsocketListenAddr(ws_listen_addr))?;
+    }
+
+    let trust_mode = match raw.trust.mode.as_deref() {
+        Some("explicit") => {
+            let peer_public_keys = raw
+                .trust
+                .peer_public_keys
+                .unwrap_or_default()
+                .iter()
+                .filter_map(|s| hex::decode(s).ok())
+                .collect();
+            TrustMode::Explicit { peer_public_keys }
+        }
+        _ => {
+            let secret = env_override("ENARX_WASMLDR_SHARED_SECRET")
+                .or(raw.trust.shared_secret)
+                .unwrap_or_else(|| "enarx-wasmldr-dev-secret".to_string());
+            if secret.is_empty() {
+                return Err(ConfigError::EmptySharedSecret);
+            }
+            TrustMode::SharedSecret(secret)
+        }
+    };
+    config.trust_mode = trust_mode;
+
+    Ok(config)
+}
+
+fn env_override(name: &str) -> Option<String> {
+    std::env::var(name).ok()
+}
+
+/// This Keep's static X25519 keypair plus the raw (32-byte) X25519 public
+/// keys it should accept from a peer, derived according to
+/// `config.trust_mode`.
+pub struct Identity {
+    pub keypair: PKey<Private>,
+    pub trusted_peer_keys: Vec<Vec<u8>>,
+}
+
+pub fn load_identity(config: &Config) -> Result<Identity, openssl::error::ErrorStack> {
+    match &config.trust_mode {
+        TrustMode::SharedSecret(secret) => {
+            let keypair = derive_keypair_from_secret(secret)?;
+            let public_key = keypair.raw_public_key()?;
+            Ok(Identity {
+                keypair,
+                //Shared-secret mode: the only peer we trust is whoever else
+                // was handed the same secret, i.e. whoever derives the same
+                // keypair we just did.
+                trusted_peer_keys: vec![public_key],
+            })
+        }
+        TrustMode::Explicit { peer_public_keys } => {
+            let mut seed = [0u8; 32];
+            rand_bytes(&mut seed)?;
+            let keypair = PKey::private_key_from_raw_bytes(&seed, Id::X25519)?;
+            Ok(Identity {
+                keypair,
+                trusted_peer_keys: peer_public_keys.clone(),
+            })
+        }
+    }
+}
+
+/// Deterministically turn a secret string into an X25519 keypair.
+///
+/// This is just "hash the secret down to a 32-byte seed"; it's only meant
+/// to give two operators who share the secret out of band the same keypair
+/// without a real KDF ceremony.
+fn derive_keypair_from_secret(secret: &str) -> Result<PKey<Private>, openssl::error::ErrorStack> {
+    let seed = hash(MessageDigest::sha256(), secret.as_bytes())?;
+    PKey::private_key_from_raw_bytes(&seed, Id::X25519)
+}
+
+/// Shared-secret ECDH between our static key and a peer's static public key,
+/// used by `handshake.rs` as one of the two DH inputs that feed session-key
+/// derivation.
+pub fn static_dh(
+    our_keypair: &PKey<Private>,
+    peer_public_key: &[u8],
+) -> Result<Vec<u8>, openssl::error::ErrorStack> {
+    let peer_key = PKey::public_key_from_raw_bytes(peer_public_key, Id::X25519)?;
+    let mut deriver = Deriver::new(our_keypair)?;
+    deriver.set_peer(&peer_key)?;
+    deriver.derive_to_vec()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lazy_static::lazy_static;
+    use std::sync::Mutex;
+
+    lazy_static! {
+        //`load()` reads process-global env vars, so tests that set them
+        //can't be allowed to run concurrently with each other.
+        static ref ENV_LOCK: Mutex<()> = Mutex::new(());
+    }
+
+    fn load_with_env(vars: &[(&str, &str)]) -> Result<Config, ConfigError> {
+        let _guard = ENV_LOCK.lock().unwrap();
+        for (name, value) in vars {
+            std::env::set_var(name, value);
+        }
+        let result = load(None);
+        for (name, _) in vars {
+            std::env::remove_var(name);
+        }
+        result
+    }
+
+    #[test]
+    fn rejects_unparseable_key_length() {
+        let result = load_with_env(&[("ENARX_WASMLDR_KEY_LENGTH", "2048bits")]);
+        assert!(matches!(result, Err(ConfigError::InvalidKeyLength(_))));
+    }
+
+    #[test]
+    fn rejects_rsa_key_length_too_small() {
+        let result = load_with_env(&[("ENARX_WASMLDR_KEY_LENGTH", "8")]);
+        assert!(matches!(result, Err(ConfigError::KeyLengthTooSmall(8))));
+    }
+
+    #[test]
+    fn rejects_unknown_key_source() {
+        let result = load_with_env(&[("ENARX_WASMLDR_KEY_SOURCE", "retrieve-existing")]);
+        assert!(matches!(result, Err(ConfigError::UnknownKeySource(_))));
+    }
+
+    #[test]
+    fn rejects_malformed_cert_country() {
+        let result = load_with_env(&[("ENARX_WASMLDR_CERT_COUNTRY", "USA")]);
+        assert!(matches!(result, Err(ConfigError::InvalidCertCountry(_))));
+    }
+
+    #[test]
+    fn accepts_a_well_formed_override_set() {
+        let config = load_with_env(&[
+            ("ENARX_WASMLDR_KEY_LENGTH", "2048"),
+            ("ENARX_WASMLDR_CERT_COUNTRY", "US"),
+        ])
+        .unwrap();
+        assert_eq!(config.key_length, 2048);
+        assert_eq!(config.cert.country, "US");
+    }
+}
@@ -35,70 +35,294 @@
 //#![feature(proc_macro_hygiene, decl_macro)]
 
 mod attestation;
+mod batch;
 mod bundle;
 mod config;
 mod handle;
+mod handshake;
 mod socket;
 mod virtfs;
 mod workload;
 
 use koine::*;
+use lazy_static::lazy_static;
 use log::info;
-use openssl::asn1::Asn1Time;
+use openssl::asn1::{Asn1Object, Asn1OctetString, Asn1Time};
 use openssl::hash::MessageDigest;
 use openssl::pkey::PKey;
 use openssl::pkey::Private;
 use openssl::rsa::*;
+use openssl::x509::X509Extension;
 use serde_cbor::{de, to_vec};
+use std::collections::HashMap;
 use std::error::Error;
 use std::fmt;
-use std::net::{IpAddr, SocketAddr};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
 //#[cfg(unix)]
 use sys_info::*;
 use warp::Filter;
 
-pub const KEY_SOURCE: &str = "generate";
+lazy_static! {
+    //Handshake sessions in progress or established, keyed by a server-issued
+    // session id. A real deployment would want these to expire - see the
+    // TODO in `handshake_init` below.
+    static ref SESSIONS: Mutex<HashMap<String, handshake::Session>> = Mutex::new(HashMap::new());
+    //Workloads currently running via `spawn_workload_launch`. The Keep only
+    //exits once this drops back to zero, so one batch's fast workload can't
+    //tear down the process out from under its still-running batchmates.
+    static ref LAUNCHES_IN_FLIGHT: AtomicUsize = AtomicUsize::new(0);
+    static ref ANY_LAUNCH_FAILED: AtomicBool = AtomicBool::new(false);
+}
 #[cfg(unix)]
 #[tokio::main(basic_scheduler)]
 async fn main() {
     //This required when calling from Rust std::process::command.  Recorded
     // to allow debugging.
     //    let args: Vec<String> = std::env::args().skip(1).collect();
-    let _args: Vec<String> = std::env::args().collect();
+    let args: Vec<String> = std::env::args().collect();
 
     //TODO - the mechanism for binding to an IP address is currently undefined.
     // It is expected that a new bridge will be created, to which this process
     //  will then bind.
 
-    //FIXME - hard-coding for now
-    //    let listen_address: &str = "127.0.0.1";
-    //let listen_address: &str = "192.168.1.203";
-    //This is the IP address of rome.sev.lab.enarx.dev (2021-01-07)
-    let listen_address: &str = "147.75.68.181";
-    //    let listen_address: &str = &args[0];
-    //FIXME - hard-coding for now
-    let listen_port: &str = "3040";
-    //    let listen_port: &str = &args[1];
-
-    let listen_socketaddr = SocketAddr::new(
-        listen_address.parse::<IpAddr>().unwrap(),
-        listen_port.parse().unwrap(),
-    );
-    let (server_key, server_cert) = get_credentials_bytes(listen_address);
-
-    // POST /workload
-    let workload = warp::post()
+    //Config path can be given as the first CLI arg, or ENARX_WASMLDR_CONFIG;
+    //either is optional, in which case we fall back to the same defaults
+    //this binary used to have hardcoded (see `config::Config::defaults`).
+    let config_path_arg = args.get(1).map(String::as_str);
+    let keep_config = config::load(config_path_arg).unwrap_or_else(|e| {
+        eprintln!("error: invalid configuration: {}", e);
+        std::process::exit(1);
+    });
+
+    let listen_socketaddr = keep_config.listen_addr;
+    let (server_key, server_cert) = get_credentials_bytes(&keep_config);
+
+    let identity =
+        Arc::new(config::load_identity(&keep_config).expect("failed to derive Keep identity"));
+    let keep_config = Arc::new(keep_config);
+
+    //Batches concurrent workload submissions under one Merkle-tree-signed
+    //attestation response - see batch.rs. 32 requests or 50ms, whichever
+    //comes first, so a lone submitter isn't stuck waiting on a full batch.
+    let batch_attestor = Arc::new(batch::BatchAttestor::new(32));
+    batch::spawn_periodic_flush(batch_attestor.clone(), std::time::Duration::from_millis(50));
+
+    // POST /handshake - mutual-auth handshake, returns our half plus a
+    // session id to present to /workload/<id>.
+    let handshake_route = warp::post()
+        .and(warp::path("handshake"))
+        .and(warp::body::bytes())
+        .and(with_state(keep_config.clone(), identity.clone()))
+        .and_then(handshake_init);
+
+    // POST /workload/<session_id> - CBOR `Workload`, encrypted under the
+    // session keys negotiated via /handshake.
+    let workload_route = warp::post()
         .and(warp::path("workload"))
+        .and(warp::path::param())
         .and(warp::body::bytes())
+        .and(with_batch_attestor(batch_attestor.clone()))
         .and_then(payload_launch);
 
-    let routes = workload;
-    warp::serve(routes)
-        .tls()
-        .cert(&server_cert)
-        .key(&server_key)
-        .run(listen_socketaddr)
-        .await;
+    let routes = handshake_route.or(workload_route);
+
+    //Both listeners need to be awaited, not just whichever one is first in
+    //program order - a websocket-only `Transport` would otherwise spawn the
+    //websocket listener without awaiting it, fall through the disabled
+    //raw-TLS block, and return out of `main` (tearing the whole runtime
+    //down) without ever having served anything.
+    let raw_tls_fut = {
+        let routes = routes.clone();
+        let keep_config = keep_config.clone();
+        async move {
+            if keep_config.transport.wants_raw_tls() {
+                warp::serve(routes)
+                    .tls()
+                    .cert(&server_cert)
+                    .key(&server_key)
+                    .run(listen_socketaddr)
+                    .await;
+            }
+        }
+    };
+    let websocket_fut = {
+        let keep_config = keep_config.clone();
+        let identity = identity.clone();
+        async move {
+            if keep_config.transport.wants_websocket() {
+                //A separate listener, since a front-end proxy relaying
+                //through a firewall is typically reachable at a different
+                //host/port than the raw-TLS socket above.
+                serve_websocket(keep_config.websocket_listen_addr, keep_config, identity).await;
+            }
+        }
+    };
+    tokio::join!(raw_tls_fut, websocket_fut);
+}
+
+/// Alongside the raw-TLS socket, accept workloads tunnelled over a
+/// WebSocket upgrade at `GET /workload/ws/<session_id>` - for tenants
+/// behind a proxy that only forwards HTTP(S)/WebSocket traffic. Exposes its
+/// own `/handshake` route (sessions are shared with the raw-TLS listener
+/// via the global `SESSIONS` map) so a tenant reachable only over
+/// WebSocket can still complete the same mutual-auth handshake before
+/// submitting a workload - this frames the same encrypted `Workload` bytes
+/// the raw-TLS path expects and reuses the same session/decrypt/launch
+/// logic, rather than trusting whatever the socket hands it.
+async fn serve_websocket(
+    addr: SocketAddr,
+    keep_config: Arc<config::Config>,
+    identity: Arc<config::Identity>,
+) {
+    let handshake_route = warp::post()
+        .and(warp::path("handshake"))
+        .and(warp::body::bytes())
+        .and(with_state(keep_config, identity))
+        .and_then(handshake_init);
+
+    let ws_route = warp::get()
+        .and(warp::path("workload"))
+        .and(warp::path("ws"))
+        .and(warp::path::param())
+        .and(warp::ws())
+        .map(|session_id: String, ws: warp::ws::Ws| {
+            ws.on_upgrade(move |socket| handle_websocket_workload(socket, session_id))
+        });
+
+    warp::serve(handshake_route.or(ws_route)).run(addr).await;
+}
+
+async fn handle_websocket_workload(mut socket: warp::ws::WebSocket, session_id: String) {
+    use futures::{SinkExt, StreamExt};
+
+    while let Some(Ok(msg)) = socket.next().await {
+        if !msg.is_binary() {
+            continue;
+        }
+        let framed = msg.into_bytes();
+
+        //Same authentication as the raw-TLS path: only a peer holding a
+        //session key from a completed handshake can produce a frame that
+        //decrypts here.
+        let workload_bytes = {
+            let mut sessions = SESSIONS.lock().unwrap();
+            let session = match sessions.get_mut(&session_id) {
+                Some(session) => session,
+                None => {
+                    let _ = socket
+                        .send(warp::ws::Message::text("unknown session"))
+                        .await;
+                    let _ = socket.close().await;
+                    return;
+                }
+            };
+            match session.decrypt(&framed) {
+                Ok(plaintext) => plaintext,
+                Err(e) => {
+                    let _ = socket
+                        .send(warp::ws::Message::text(format!(
+                            "could not decrypt workload: {}",
+                            e
+                        )))
+                        .await;
+                    let _ = socket.close().await;
+                    return;
+                }
+            }
+        };
+
+        let workload: Workload = match de::from_slice(&workload_bytes) {
+            Ok(workload) => workload,
+            Err(_) => {
+                let _ = socket
+                    .send(warp::ws::Message::text("Payload parsing problem"))
+                    .await;
+                let _ = socket.close().await;
+                return;
+            }
+        };
+
+        //Send the CBOR completion status as a binary frame and close the
+        //socket first, since spawn_workload_launch below runs (and may exit
+        //the process) asynchronously.
+        let comms_complete = CommsComplete::Success;
+        let cbor_reply_body: Vec<u8> = to_vec(&comms_complete).unwrap();
+        let _ = socket.send(warp::ws::Message::binary(cbor_reply_body)).await;
+        let _ = socket.close().await;
+
+        spawn_workload_launch(workload);
+        break;
+    }
+}
+
+/// Thread the Keep's trust config and derived identity into a warp handler.
+fn with_state(
+    keep_config: Arc<config::Config>,
+    identity: Arc<config::Identity>,
+) -> impl Filter<Extract = ((Arc<config::Config>, Arc<config::Identity>),), Error = std::convert::Infallible>
+       + Clone {
+    warp::any().map(move || (keep_config.clone(), identity.clone()))
+}
+
+/// Thread the shared batch-attestation aggregator into a warp handler.
+fn with_batch_attestor(
+    attestor: Arc<batch::BatchAttestor>,
+) -> impl Filter<Extract = (Arc<batch::BatchAttestor>,), Error = std::convert::Infallible> + Clone
+{
+    warp::any().map(move || attestor.clone())
+}
+
+/// Responder side of the handshake: the peer posts its
+/// `handshake::HandshakeMessage` (CBOR), we check its static key against
+/// our trust set, generate our own ephemeral key, and hand back our half
+/// plus the session id the peer should use for `/workload/<id>`.
+async fn handshake_init<B: warp::Buf>(
+    bytes: B,
+    state: (Arc<config::Config>, Arc<config::Identity>),
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let (keep_config, identity) = state;
+    let peer_message: handshake::HandshakeMessage = match de::from_slice(bytes.bytes()) {
+        Ok(msg) => msg,
+        Err(_) => return Err(warp::reject::custom(LocalCborErr::new("bad handshake message"))),
+    };
+
+    let (reply, session) = match handshake::respond(
+        &keep_config,
+        &identity.keypair,
+        &identity.trusted_peer_keys,
+        &peer_message,
+    ) {
+        Ok(result) => result,
+        Err(e) => {
+            return Err(warp::reject::custom(LocalCborErr::new(&format!(
+                "handshake failed: {}",
+                e
+            ))))
+        }
+    };
+
+    //TODO - session ids should expire; right now a completed handshake's
+    // session lives until the process restarts.
+    let session_id = {
+        let mut id_bytes = [0u8; 16];
+        openssl::rand::rand_bytes(&mut id_bytes).unwrap();
+        hex::encode(id_bytes)
+    };
+    SESSIONS.lock().unwrap().insert(session_id.clone(), session);
+
+    #[derive(serde::Serialize)]
+    struct HandshakeReply {
+        session_id: String,
+        message: handshake::HandshakeMessage,
+    }
+    let body = to_vec(&HandshakeReply {
+        session_id,
+        message: reply,
+    })
+    .unwrap();
+    Ok(body)
 }
 
 fn create_new_runtime(recvd_data: &[u8]) -> Result<bool, String> {
@@ -116,60 +340,134 @@ fn create_new_runtime(recvd_data: &[u8]) -> Result<bool, String> {
     Ok(true)
 }
 
-async fn payload_launch<B: warp::Buf>(bytes: B) -> Result<impl warp::Reply, warp::Rejection> {
+/// Run `workload` to completion on its own blocking task, and only call
+/// `std::process::exit` once every workload launched this way - across
+/// every batch, not just this one - has finished.
+///
+/// A single batch (the whole point of chunk0-5) can admit several
+/// concurrent submitters; each gets its own call to this function instead
+/// of the Keep exiting the moment the first one to finish calls
+/// `create_new_runtime`, which would otherwise kill every other workload
+/// still in flight.
+fn spawn_workload_launch(workload: Workload) {
+    LAUNCHES_IN_FLIGHT.fetch_add(1, Ordering::SeqCst);
+    tokio::task::spawn(async move {
+        let result =
+            tokio::task::spawn_blocking(move || create_new_runtime(&workload.wasm_binary)).await;
+        match result {
+            Ok(Ok(_)) => {}
+            Ok(Err(err)) => {
+                eprintln!("error: {:?}", err);
+                ANY_LAUNCH_FAILED.store(true, Ordering::SeqCst);
+            }
+            Err(join_err) => {
+                eprintln!("error: workload task panicked: {:?}", join_err);
+                ANY_LAUNCH_FAILED.store(true, Ordering::SeqCst);
+            }
+        }
+
+        //If we were the last workload in flight (from this batch or any
+        //other), it's now safe to exit.
+        if LAUNCHES_IN_FLIGHT.fetch_sub(1, Ordering::SeqCst) == 1 {
+            std::process::exit(if ANY_LAUNCH_FAILED.load(Ordering::SeqCst) {
+                1
+            } else {
+                0
+            });
+        }
+    });
+}
+
+/// What a submitter gets back for a workload that was admitted: the usual
+/// completion status, plus a Merkle proof it can check (one signature
+/// verification + O(log n) hashing) against `batch.rs`'s batched
+/// attestation.
+#[derive(serde::Serialize)]
+struct LaunchReceipt {
+    status: CommsComplete,
+    attestation: batch::AttestationResponse,
+}
+
+async fn payload_launch<B: warp::Buf>(
+    session_id: String,
+    bytes: B,
+    batch_attestor: Arc<batch::BatchAttestor>,
+) -> Result<impl warp::Reply, warp::Rejection> {
     //println!(
     //    "payload_launch bytes.bytes().len() = {}",
     //    bytes.bytes().len()
     //);
     let wbytes: &[u8] = bytes.bytes();
     //println!("payload_launch received {} bytes", wbytes.len());
-    let workload_bytes = wbytes.as_ref();
-
-    //deserialise the Vector into a Payload (and handle errors)
-    let workload: Workload;
-    match de::from_slice(&workload_bytes) {
-        Ok(wl) => {
-            workload = wl;
-
-            //println!("Received a workload: {}", workload.human_readable_info);
-
-            //Exit after completion
-            std::process::exit(match create_new_runtime(&workload.wasm_binary) {
-                Ok(_) => {
-                    //println!("Success - exiting");
-                    0
-                }
-                Err(err) => {
-                    eprintln!("error: {:?}", err);
-                    1
-                }
-            });
-
-            //TODO - does this code need to be here?
-            #[allow(unreachable_code)]
-            {
-                let comms_complete = CommsComplete::Success;
-                let cbor_reply_body: Vec<u8> = to_vec(&comms_complete).unwrap();
-                //let cbor_reply: CborReply = CborReply {
-                //    msg: cbor_reply_body,
-                //};
-                //Ok(cbor_reply)
-                Ok(cbor_reply_body)
+    let framed = wbytes.as_ref();
+
+    //Only a peer who completed the handshake above holds a session key, so
+    //this is what actually enforces "only trusted tenants can submit
+    //workloads" - an untrusted caller can't produce a frame that decrypts.
+    let workload_bytes = {
+        let mut sessions = SESSIONS.lock().unwrap();
+        let session = match sessions.get_mut(&session_id) {
+            Some(session) => session,
+            None => return Err(warp::reject::custom(LocalCborErr::new("unknown session"))),
+        };
+        match session.decrypt(framed) {
+            Ok(plaintext) => plaintext,
+            Err(e) => {
+                return Err(warp::reject::custom(LocalCborErr::new(&format!(
+                    "could not decrypt workload: {}",
+                    e
+                ))))
             }
         }
+    };
+
+    //deserialise the Vector into a Payload (and handle errors)
+    let workload: Workload = match de::from_slice(workload_bytes.as_slice()) {
+        Ok(wl) => wl,
         Err(_) => {
             println!("Payload parsing problem");
-            let cbore = LocalCborErr::new("Payload parsing problem");
-            Err(warp::reject::custom(cbore))
+            return Err(warp::reject::custom(LocalCborErr::new(
+                "Payload parsing problem",
+            )));
         }
-    }
+    };
+
+    //println!("Received a workload: {}", workload.human_readable_info);
+
+    let request_hash = openssl::sha::sha256(&workload_bytes);
+    let attestation = match batch_attestor.submit(request_hash).await {
+        Ok(attestation) => attestation,
+        Err(_) => {
+            return Err(warp::reject::custom(LocalCborErr::new(
+                "batch attestation channel closed before this workload's batch was signed",
+            )))
+        }
+    };
+
+    let receipt = LaunchReceipt {
+        status: CommsComplete::Success,
+        attestation,
+    };
+    let cbor_reply_body: Vec<u8> = to_vec(&receipt).unwrap();
+
+    //Launch the workload only after we've handed the receipt above back to
+    //warp to send - std::process::exit doesn't give the response a chance
+    //to flush if we call it inline here. spawn_workload_launch defers the
+    //actual exit until every concurrently-launched workload (this batch's
+    //and any other's) has finished.
+    tokio::task::spawn(async move {
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        spawn_workload_launch(workload);
+    });
+
+    Ok(cbor_reply_body)
 }
 
-fn get_credentials_bytes(listen_addr: &str) -> (Vec<u8>, Vec<u8>) {
-    let (key, cert) = match KEY_SOURCE {
-        "generate" => (generate_credentials(&listen_addr)),
+fn get_credentials_bytes(config: &config::Config) -> (Vec<u8>, Vec<u8>) {
+    let (key, cert) = match config.key_source.as_str() {
+        "generate" => (generate_credentials(config)),
         //no match!
-        _ => panic!("No match for credentials source"),
+        other => panic!("No match for credentials source {:?}", other),
     };
     (key, cert)
 }
@@ -210,9 +508,8 @@ fn retrieve_existing_key() -> Option<Rsa<Private>> {
 }
 
 //TODO - this is vital code, and needs to be carefully audited!
-fn generate_credentials(listen_addr: &str) -> (Vec<u8>, Vec<u8>) {
-    //TODO - parameterise key_length?
-    let key_length = 2048;
+fn generate_credentials(config: &config::Config) -> (Vec<u8>, Vec<u8>) {
+    let key_length = config.key_length;
     let key_opt = retrieve_existing_key();
     let key: Rsa<Private> = match key_opt {
         Some(key) => key,
@@ -222,19 +519,24 @@ fn generate_credentials(listen_addr: &str) -> (Vec<u8>, Vec<u8>) {
     let pkey = PKey::from_rsa(key.clone()).unwrap();
 
     let myhostname = hostname().unwrap();
+    let listen_address = config.listen_addr.ip().to_string();
     //println!(
     //    "Create a certificate for {} ({})",
-    //    &listen_addr, &myhostname
+    //    &listen_address, &myhostname
     //);
 
     let mut x509_name = openssl::x509::X509NameBuilder::new().unwrap();
-    x509_name.append_entry_by_text("C", "GB").unwrap();
-    x509_name.append_entry_by_text("O", "enarx-test").unwrap();
-    //FIXME - we should use &listen-addr, but this fails
     x509_name
-        .append_entry_by_text("subjectAltName", &listen_addr)
+        .append_entry_by_text("C", &config.cert.country)
         .unwrap();
-    //x509_name.append_entry_by_text("CN", &listen_addr).unwrap();
+    x509_name
+        .append_entry_by_text("O", &config.cert.organization)
+        .unwrap();
+    //FIXME - we should use &listen-address, but this fails
+    x509_name
+        .append_entry_by_text("subjectAltName", &listen_address)
+        .unwrap();
+    //x509_name.append_entry_by_text("CN", &listen_address).unwrap();
     //x509_name.append_entry_by_text("CN", "nail").unwrap();
     x509_name.append_entry_by_text("CN", &myhostname).unwrap();
     //TODO - include SGX case, where we're adding public key (?) information
@@ -245,12 +547,32 @@ fn generate_credentials(listen_addr: &str) -> (Vec<u8>, Vec<u8>) {
     if let Err(e) = x509_builder.set_not_before(&Asn1Time::days_from_now(0).unwrap()) {
         panic!("Problem creating cert {}", e)
     }
-    if let Err(e) = x509_builder.set_not_after(&Asn1Time::days_from_now(7).unwrap()) {
+    if let Err(e) =
+        x509_builder.set_not_after(&Asn1Time::days_from_now(config.cert.validity_days).unwrap())
+    {
         panic!("Problem creating cert {}", e)
     }
 
     x509_builder.set_subject_name(&x509_name).unwrap();
     x509_builder.set_pubkey(&pkey).unwrap();
+
+    //RA-TLS: bind this cert to the enclave's attestation evidence, so a
+    // peer can check it's really talking to a genuine Keep instead of just
+    // trusting whoever answers on the socket (see attestation.rs).
+    let pubkey_der = pkey.public_key_to_der().unwrap();
+    let report_data: [u8; 32] = openssl::sha::sha256(&pubkey_der);
+    match attestation::attest_for_report_data(&report_data) {
+        Ok(Some((oid, quote))) => {
+            if let Err(e) = add_attestation_extension(&mut x509_builder, oid, &quote) {
+                eprintln!("warning: could not embed attestation extension: {}", e);
+            }
+        }
+        Ok(None) => {
+            //Not running under SGX/SEV - ship a plain cert, same as before.
+        }
+        Err(e) => eprintln!("warning: attestation failed, shipping unattested cert: {}", e),
+    }
+
     x509_builder.sign(&pkey, MessageDigest::sha256()).unwrap();
     let certificate = x509_builder.build();
 
@@ -261,6 +583,95 @@ fn generate_credentials(listen_addr: &str) -> (Vec<u8>, Vec<u8>) {
     )
 }
 
+/// Add a custom v3 extension carrying the raw attestation evidence under
+/// `oid` (see `attestation::OID_SGX_QUOTE` / `OID_SEV_REPORT`).
+fn add_attestation_extension(
+    builder: &mut openssl::x509::X509Builder,
+    oid: &str,
+    quote: &[u8],
+) -> Result<(), openssl::error::ErrorStack> {
+    let object = Asn1Object::create_nid_from_str(oid).or_else(|_| Asn1Object::from_str(oid))?;
+    let contents = Asn1OctetString::new_from_bytes(quote)?;
+    let ext = X509Extension::new_from_der(&object, false, &contents)?;
+    builder.append_extension(ext)
+}
+
+/// Client/peer-side counterpart to `add_attestation_extension`: pull the
+/// attestation evidence back out of a Keep's leaf certificate so it can be
+/// handed to `attestation::verify_embedded_attestation`.
+///
+/// Note that today that only gets a caller as far as
+/// `VerifyOutcome::ReportDataBoundOnly` - there is no vendor-root chain
+/// check yet, so this is not a complete RA-TLS channel-binding check on its
+/// own.
+///
+/// The safe `openssl::x509::X509Ref` API only has typed getters for
+/// well-known extensions (SANs, AKI, etc.) - there's no generic "give me
+/// every extension as a raw (OID, DER payload) pair" accessor, which is
+/// what a private OID like ours needs. So this drops down to the same
+/// libssl calls the `openssl` crate's own safe wrappers are themselves
+/// built on, via `openssl-sys`, rather than a safe API that doesn't exist.
+#[allow(dead_code)]
+fn extract_attestation_extension(
+    cert: &openssl::x509::X509,
+) -> Option<attestation::EmbeddedEvidence> {
+    use foreign_types::ForeignTypeRef;
+
+    // Safety: `cert_ptr` outlives this whole function (it's borrowed from
+    // `cert`, which the caller keeps alive), and every pointer returned by
+    // X509_get_ext/X509_EXTENSION_get_object/_get_data is either null
+    // (checked below) or owned by - and valid for as long as - the
+    // certificate/extension it came from.
+    unsafe {
+        let cert_ptr = cert.as_ptr();
+        let ext_count = openssl_sys::X509_get_ext_count(cert_ptr);
+        for i in 0..ext_count {
+            let ext = openssl_sys::X509_get_ext(cert_ptr, i);
+            if ext.is_null() {
+                continue;
+            }
+            let object = openssl_sys::X509_EXTENSION_get_object(ext);
+            if object.is_null() {
+                continue;
+            }
+            let mut oid_buf = [0u8; 80];
+            let oid_len = openssl_sys::OBJ_obj2txt(
+                oid_buf.as_mut_ptr() as *mut libc::c_char,
+                oid_buf.len() as libc::c_int,
+                object,
+                1, // no_name - we want the dotted-decimal OID, not a short name
+            );
+            if oid_len <= 0 {
+                continue;
+            }
+            let oid = match std::str::from_utf8(&oid_buf[..oid_len as usize]) {
+                Ok(oid) => oid,
+                Err(_) => continue,
+            };
+            if oid != attestation::OID_SGX_QUOTE && oid != attestation::OID_SEV_REPORT {
+                continue;
+            }
+
+            let data = openssl_sys::X509_EXTENSION_get_data(ext);
+            if data.is_null() {
+                continue;
+            }
+            let quote_ptr = openssl_sys::ASN1_STRING_get0_data(data as *mut openssl_sys::ASN1_STRING);
+            let quote_len = openssl_sys::ASN1_STRING_length(data as *mut openssl_sys::ASN1_STRING);
+            if quote_ptr.is_null() || quote_len < 0 {
+                continue;
+            }
+            let quote = std::slice::from_raw_parts(quote_ptr, quote_len as usize).to_vec();
+
+            return Some(attestation::EmbeddedEvidence {
+                oid: oid.to_string(),
+                quote,
+            });
+        }
+    }
+    None
+}
+
 #[derive(Debug)]
 struct LocalCborErr {
     details: String,
@@ -286,4 +697,66 @@ impl Error for LocalCborErr {
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Round-trips a quote through `add_attestation_extension` /
+    /// `extract_attestation_extension` against a real, signed `X509` - the
+    /// only way to actually exercise the raw-FFI extension lookup above.
+    #[test]
+    fn attestation_extension_round_trips_through_a_real_certificate() {
+        let rsa = Rsa::generate(2048).unwrap();
+        let pkey = PKey::from_rsa(rsa).unwrap();
+
+        let mut name_builder = openssl::x509::X509NameBuilder::new().unwrap();
+        name_builder.append_entry_by_text("CN", "test").unwrap();
+        let name = name_builder.build();
+
+        let mut builder = openssl::x509::X509::builder().unwrap();
+        builder.set_subject_name(&name).unwrap();
+        builder.set_pubkey(&pkey).unwrap();
+        builder
+            .set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        builder
+            .set_not_after(&Asn1Time::days_from_now(1).unwrap())
+            .unwrap();
+
+        let quote = vec![0xabu8; 32];
+        add_attestation_extension(&mut builder, attestation::OID_SGX_QUOTE, &quote).unwrap();
+        builder.sign(&pkey, MessageDigest::sha256()).unwrap();
+        let cert = builder.build();
+
+        let evidence =
+            extract_attestation_extension(&cert).expect("embedded extension round-trips");
+        assert_eq!(evidence.oid, attestation::OID_SGX_QUOTE);
+        assert_eq!(evidence.quote, quote);
+    }
+
+    #[test]
+    fn extract_attestation_extension_is_none_without_one() {
+        let rsa = Rsa::generate(2048).unwrap();
+        let pkey = PKey::from_rsa(rsa).unwrap();
+
+        let mut name_builder = openssl::x509::X509NameBuilder::new().unwrap();
+        name_builder.append_entry_by_text("CN", "test").unwrap();
+        let name = name_builder.build();
+
+        let mut builder = openssl::x509::X509::builder().unwrap();
+        builder.set_subject_name(&name).unwrap();
+        builder.set_pubkey(&pkey).unwrap();
+        builder
+            .set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        builder
+            .set_not_after(&Asn1Time::days_from_now(1).unwrap())
+            .unwrap();
+        builder.sign(&pkey, MessageDigest::sha256()).unwrap();
+        let cert = builder.build();
+
+        assert!(extract_attestation_extension(&cert).is_none());
+    }
+}
+
 impl warp::reject::Reject for LocalCborErr {}